@@ -2,18 +2,339 @@
 
 #![warn(missing_docs)]
 
-use ogg::writing::PacketWriteEndInfo;
-use ogg::{Packet, PacketReader, PacketWriter};
+use base64::engine::{general_purpose::STANDARD, Engine};
+use ogg::{Packet, PacketReader};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::io::{Cursor, Read, Seek};
+use std::io::{self, Cursor, Read, Seek, Write};
 
 /// A comment header.
 pub type CommentHeader = lewton::header::CommentHeader;
 
+/// Errors that can occur while reading or writing Vorbis/Opus/Speex comment metadata.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// An I/O error occurred.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// An error occurred reading the underlying Ogg container.
+    #[error("Ogg error: {0}")]
+    Ogg(#[from] ogg::OggReadError),
+
+    /// The identification packet did not match a known codec.
+    #[error("unrecognized codec identification packet")]
+    UnknownCodec,
+
+    /// The comment packet did not start with the expected signature for its codec.
+    #[error("comment packet is missing the expected signature")]
+    MissingSignature,
+
+    /// A declared length (vendor, comment count, comment string, or a
+    /// picture block field) exceeded the number of bytes actually remaining
+    /// in the packet or picture block.
+    #[error("declared length {declared} exceeds the {remaining} bytes remaining in the packet")]
+    LengthOutOfBounds {
+        /// The declared length.
+        declared: usize,
+        /// The number of bytes actually remaining in the packet or picture block.
+        remaining: usize,
+    },
+
+    /// A vendor string, comment string, or picture MIME type or description
+    /// was not valid UTF-8.
+    #[error("invalid UTF-8 in comment data: {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+
+    /// A vendor string, comment count, or comment string was too large to
+    /// encode in a 32-bit length field.
+    #[error("value too large to encode in a 32-bit length field")]
+    TooLarge,
+
+    /// A tag name was not a spec-legal Vorbis comment field name.
+    #[error("invalid Vorbis comment field name: {0:?}")]
+    InvalidFieldName(String),
+
+    /// A line passed to `from_text` had no `=` separating its key from its value.
+    #[error("text line is missing a '=' separator: {0:?}")]
+    MalformedTextLine(String),
+
+    /// A value passed to `from_text` contained a backslash escape this
+    /// crate doesn't recognize.
+    #[error("invalid escape sequence in text value")]
+    InvalidEscape,
+
+    /// A `METADATA_BLOCK_PICTURE` comment's value was not valid base64.
+    #[error("invalid base64 in METADATA_BLOCK_PICTURE comment: {0}")]
+    InvalidBase64(#[from] base64::DecodeError),
+}
+
+/// Check whether `name` is a spec-legal Vorbis comment field name: printable
+/// ASCII in the range 0x20-0x7D, excluding `=` (0x3D). Field names outside
+/// this range produce a comment packet that other decoders may misparse.
+pub fn validate_comment_field_name(name: &str) -> bool {
+    name.bytes().all(|b| (0x20..=0x7D).contains(&b) && b != 0x3D)
+}
+
+/// Escape a tag value for [`VorbisComments::to_text`]: backslashes,
+/// newlines and carriage returns would otherwise corrupt the line-oriented
+/// dump, so they're escaped as `\\`, `\n` and `\r`. Field names can't
+/// contain `=`, so a value is free to contain literal `=` unescaped; the
+/// first `=` on a line is always the key/value separator.
+fn escape_text_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverse [`escape_text_value`].
+fn unescape_text_value(value: &str) -> Result<String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            _ => return Err(Error::InvalidEscape),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// A specialized `Result` type for this crate's fallible operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The well-known tag name under which cover art is stored, per the Vorbis
+/// comment convention shared with FLAC (`METADATA_BLOCK_PICTURE`).
+const PICTURE_TAG: &str = "metadata_block_picture";
+
+/// A picture (e.g. cover art) embedded in a `METADATA_BLOCK_PICTURE` comment,
+/// using the same binary layout as FLAC's `PICTURE` metadata block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    /// The picture type, using the ID3v2 APIC frame's type values (e.g. 3 = front cover).
+    pub picture_type: u32,
+    /// The MIME type of the picture data.
+    pub mime: String,
+    /// A description of the picture.
+    pub description: String,
+    /// The width of the picture in pixels.
+    pub width: u32,
+    /// The height of the picture in pixels.
+    pub height: u32,
+    /// The color depth of the picture in bits per pixel.
+    pub depth: u32,
+    /// The number of colors used for indexed-color pictures, or 0 otherwise.
+    pub colors: u32,
+    /// The raw picture data.
+    pub data: Vec<u8>,
+}
+
+/// Serialize a picture into the big-endian FLAC-style picture block.
+fn encode_picture_block(picture: &Picture) -> Vec<u8> {
+    let mime = picture.mime.as_bytes();
+    let description = picture.description.as_bytes();
+
+    let mut block = Vec::new();
+    block.extend(picture.picture_type.to_be_bytes().iter().cloned());
+    block.extend((mime.len() as u32).to_be_bytes().iter().cloned());
+    block.extend(mime.iter().cloned());
+    block.extend((description.len() as u32).to_be_bytes().iter().cloned());
+    block.extend(description.iter().cloned());
+    block.extend(picture.width.to_be_bytes().iter().cloned());
+    block.extend(picture.height.to_be_bytes().iter().cloned());
+    block.extend(picture.depth.to_be_bytes().iter().cloned());
+    block.extend(picture.colors.to_be_bytes().iter().cloned());
+    block.extend((picture.data.len() as u32).to_be_bytes().iter().cloned());
+    block.extend(picture.data.iter().cloned());
+    block
+}
+
+/// Read a big-endian `u32` at `*pos`, advancing it, rejecting one that
+/// doesn't actually fit in what's left of `block`.
+fn read_u32_be(block: &[u8], pos: &mut usize) -> Result<u32> {
+    let remaining = block.len() - *pos;
+    if remaining < 4 {
+        return Err(Error::LengthOutOfBounds {
+            declared: 4,
+            remaining,
+        });
+    }
+    let value = u32::from_be_bytes(block[*pos..*pos + 4].try_into().unwrap());
+    *pos += 4;
+    Ok(value)
+}
+
+/// Parse the big-endian FLAC-style picture block back into a `Picture`,
+/// validating every declared length against the bytes actually remaining in
+/// `block` before reading them.
+fn decode_picture_block(block: &[u8]) -> Result<Picture> {
+    let mut pos = 0;
+
+    let picture_type = read_u32_be(block, &mut pos)?;
+    let mime_len = read_u32_be(block, &mut pos)? as usize;
+    let mime = read_string(block, &mut pos, mime_len)?;
+    let description_len = read_u32_be(block, &mut pos)? as usize;
+    let description = read_string(block, &mut pos, description_len)?;
+    let width = read_u32_be(block, &mut pos)?;
+    let height = read_u32_be(block, &mut pos)?;
+    let depth = read_u32_be(block, &mut pos)?;
+    let colors = read_u32_be(block, &mut pos)?;
+    let data_len = read_u32_be(block, &mut pos)? as usize;
+    let remaining = block.len() - pos;
+    if data_len > remaining {
+        return Err(Error::LengthOutOfBounds {
+            declared: data_len,
+            remaining,
+        });
+    }
+    let data = block[pos..pos + data_len].to_vec();
+
+    Ok(Picture {
+        picture_type,
+        mime,
+        description,
+        width,
+        height,
+        depth,
+        colors,
+        data,
+    })
+}
+
+/// The Xiph/Ogg audio codec that a logical bitstream's comment header belongs
+/// to. Vorbis, Opus and Speex all carry an identical vendor/tag comment
+/// structure; they differ only in how the comment packet is framed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Vorbis, identified by the `\x01vorbis` identification packet and the
+    /// `\x03vorbis` comment header signature.
+    Vorbis,
+    /// Opus, identified by the `OpusHead` identification packet and the
+    /// `OpusTags` comment header magic.
+    Opus,
+    /// Speex, identified by the `Speex   ` identification packet; its comment
+    /// header carries no signature of its own.
+    Speex,
+}
+
+const VORBIS_IDENT_SIGNATURE: &[u8] = b"\x01vorbis";
+const VORBIS_COMMENT_SIGNATURE: &[u8] = b"\x03vorbis";
+const OPUS_IDENT_SIGNATURE: &[u8] = b"OpusHead";
+const OPUS_COMMENT_SIGNATURE: &[u8] = b"OpusTags";
+const SPEEX_IDENT_SIGNATURE: &[u8] = b"Speex   ";
+
+/// Work out which codec a logical bitstream uses from its identification packet.
+fn detect_codec(ident_packet: &[u8]) -> Option<Codec> {
+    if ident_packet.starts_with(VORBIS_IDENT_SIGNATURE) {
+        Some(Codec::Vorbis)
+    } else if ident_packet.starts_with(OPUS_IDENT_SIGNATURE) {
+        Some(Codec::Opus)
+    } else if ident_packet.starts_with(SPEEX_IDENT_SIGNATURE) {
+        Some(Codec::Speex)
+    } else {
+        None
+    }
+}
+
+/// Strip the codec-specific comment header signature, returning the
+/// remaining vendor/tag body shared by all three codecs.
+fn strip_comment_signature(codec: Codec, data: &[u8]) -> Result<&[u8]> {
+    let signature: &[u8] = match codec {
+        Codec::Vorbis => VORBIS_COMMENT_SIGNATURE,
+        Codec::Opus => OPUS_COMMENT_SIGNATURE,
+        Codec::Speex => b"",
+    };
+    if !data.starts_with(signature) {
+        return Err(Error::MissingSignature);
+    }
+    Ok(&data[signature.len()..])
+}
+
+/// Read a little-endian `u32` length prefix at `*pos`, advancing it, and
+/// reject one that doesn't actually fit in what's left of `body`.
+fn read_length(body: &[u8], pos: &mut usize) -> Result<usize> {
+    let remaining = body.len() - *pos;
+    if remaining < 4 {
+        return Err(Error::LengthOutOfBounds {
+            declared: 4,
+            remaining,
+        });
+    }
+    let len = u32::from_le_bytes(body[*pos..*pos + 4].try_into().unwrap()) as usize;
+    *pos += 4;
+    Ok(len)
+}
+
+/// Read `len` bytes at `*pos` as a UTF-8 string, advancing `pos`, rejecting a
+/// `len` that exceeds the bytes actually remaining in `body`.
+fn read_string(body: &[u8], pos: &mut usize, len: usize) -> Result<String> {
+    let remaining = body.len() - *pos;
+    if len > remaining {
+        return Err(Error::LengthOutOfBounds {
+            declared: len,
+            remaining,
+        });
+    }
+    let s = String::from_utf8(body[*pos..*pos + len].to_vec())?;
+    *pos += len;
+    Ok(s)
+}
+
+/// Parse the vendor/tag body shared by Vorbis, Opus and Speex comment
+/// headers (i.e. the comment packet with any codec-specific signature
+/// already stripped off).
+fn parse_comment_body(body: &[u8]) -> Result<CommentHeader> {
+    let mut pos = 0;
+
+    let vendor_len = read_length(body, &mut pos)?;
+    let vendor = read_string(body, &mut pos, vendor_len)?;
+
+    let comment_count = read_length(body, &mut pos)?;
+    let remaining = body.len() - pos;
+    if comment_count > remaining / 4 {
+        return Err(Error::LengthOutOfBounds {
+            declared: comment_count,
+            remaining,
+        });
+    }
+
+    let mut comment_list = Vec::with_capacity(comment_count);
+    for _ in 0..comment_count {
+        let comment_len = read_length(body, &mut pos)?;
+        let comment = read_string(body, &mut pos, comment_len)?;
+
+        let mut parts = comment.splitn(2, '=');
+        let key = parts.next().unwrap_or("").to_string();
+        let value = parts.next().unwrap_or("").to_string();
+        comment_list.push((key, value));
+    }
+
+    Ok(CommentHeader {
+        vendor,
+        comment_list,
+    })
+}
+
 /// A holder of Vorbis comments.
 pub trait VorbisComments {
-    /// Construct a VorbisComments from its contents.
-    fn from(vendor: String, comment_list: Vec<(String, String)>) -> Self;
+    /// Construct a VorbisComments from its contents, rejecting any entry
+    /// whose tag is not a spec-legal field name.
+    fn from(vendor: String, comment_list: Vec<(String, String)>) -> Result<Self>
+    where
+        Self: Sized;
 
     /// Create an empty VorbisContents.
     fn new() -> Self;
@@ -30,25 +351,70 @@ pub trait VorbisComments {
     /// Remove a tag.
     fn clear_tag(&mut self, tag: &str);
 
-    /// Add a tag.
-    fn add_tag_single(&mut self, tag: &str, value: &str);
+    /// Add a tag, rejecting a `tag` that is not a spec-legal field name.
+    fn add_tag_single(&mut self, tag: &str, value: &str) -> Result<()>;
 
-    /// Add multiple instances of a tag.
-    fn add_tag_multi(&mut self, tag: &str, values: &[&str]);
+    /// Add multiple instances of a tag, rejecting a `tag` that is not a
+    /// spec-legal field name.
+    fn add_tag_multi(&mut self, tag: &str, values: &[&str]) -> Result<()>;
 
     /// Get the vendor.
     fn get_vendor(&self) -> &str;
 
     /// Set the vendor.
     fn set_vendor(&mut self, vend: &str);
+
+    /// Attach a picture (e.g. cover art), stored as a `METADATA_BLOCK_PICTURE` comment.
+    #[allow(clippy::too_many_arguments)]
+    fn add_picture(
+        &mut self,
+        picture_type: u32,
+        mime: &str,
+        description: &str,
+        width: u32,
+        height: u32,
+        depth: u32,
+        colors: u32,
+        data: &[u8],
+    );
+
+    /// Get all pictures attached via `METADATA_BLOCK_PICTURE` comments,
+    /// rejecting the first one that isn't valid base64 or isn't a
+    /// well-formed picture block.
+    fn get_pictures(&self) -> Result<Vec<Picture>>;
+
+    /// Remove all attached pictures.
+    fn clear_pictures(&mut self);
+
+    /// Dump every tag as `KEY=value` lines, one tag per line, for hand
+    /// editing. Values are escaped so that each value round-trips through
+    /// [`VorbisComments::from_text`] byte-for-byte; keys come back
+    /// lowercased, and a key that is not a spec-legal field name makes
+    /// `from_text` reject the dump.
+    fn to_text(&self) -> String;
+
+    /// Replace all tags with the `KEY=value` lines parsed out of `s`,
+    /// unescaping each value and validating each key as a spec-legal field
+    /// name.
+    ///
+    /// Named `from_text` for symmetry with [`VorbisComments::to_text`]
+    /// rather than clippy's `from_*` convention (which expects a consuming
+    /// constructor); this is a deliberate, fixed API choice.
+    #[allow(clippy::wrong_self_convention)]
+    fn from_text(&mut self, s: &str) -> Result<()>;
 }
 
 impl VorbisComments for CommentHeader {
-    fn from(vendor: String, comment_list: Vec<(String, String)>) -> CommentHeader {
-        CommentHeader {
+    fn from(vendor: String, comment_list: Vec<(String, String)>) -> Result<CommentHeader> {
+        for (tag, _) in comment_list.iter() {
+            if !validate_comment_field_name(tag) {
+                return Err(Error::InvalidFieldName(tag.clone()));
+            }
+        }
+        Ok(CommentHeader {
             vendor,
             comment_list,
-        }
+        })
     }
 
     fn new() -> CommentHeader {
@@ -63,6 +429,7 @@ impl VorbisComments for CommentHeader {
             .comment_list
             .iter()
             .map(|comment| comment.0.to_lowercase())
+            .filter(|name| name != PICTURE_TAG)
             .collect::<Vec<String>>();
         names.sort_unstable();
         names.dedup();
@@ -91,16 +458,24 @@ impl VorbisComments for CommentHeader {
             .retain(|comment| comment.0.to_lowercase() != tag.to_lowercase());
     }
 
-    fn add_tag_single(&mut self, tag: &str, value: &str) {
+    fn add_tag_single(&mut self, tag: &str, value: &str) -> Result<()> {
+        if !validate_comment_field_name(tag) {
+            return Err(Error::InvalidFieldName(tag.to_string()));
+        }
         self.comment_list
             .push((tag.to_lowercase(), value.to_string()));
+        Ok(())
     }
 
-    fn add_tag_multi(&mut self, tag: &str, values: &[&str]) {
+    fn add_tag_multi(&mut self, tag: &str, values: &[&str]) -> Result<()> {
+        if !validate_comment_field_name(tag) {
+            return Err(Error::InvalidFieldName(tag.to_string()));
+        }
         for value in values.iter() {
             self.comment_list
                 .push((tag.to_lowercase(), value.to_string()));
         }
+        Ok(())
     }
 
     fn get_vendor(&self) -> &str {
@@ -110,31 +485,98 @@ impl VorbisComments for CommentHeader {
     fn set_vendor(&mut self, vend: &str) {
         self.vendor = vend.to_string();
     }
-}
 
-/// Write out a comment header.
-pub fn make_comment_header(header: &CommentHeader) -> Vec<u8> {
-    // Signature
-    let start = [3u8, 118, 111, 114, 98, 105, 115];
+    fn add_picture(
+        &mut self,
+        picture_type: u32,
+        mime: &str,
+        description: &str,
+        width: u32,
+        height: u32,
+        depth: u32,
+        colors: u32,
+        data: &[u8],
+    ) {
+        let block = encode_picture_block(&Picture {
+            picture_type,
+            mime: mime.to_string(),
+            description: description.to_string(),
+            width,
+            height,
+            depth,
+            colors,
+            data: data.to_vec(),
+        });
+        self.add_tag_single(PICTURE_TAG, &STANDARD.encode(block))
+            .expect("PICTURE_TAG is always a spec-legal field name");
+    }
+
+    fn get_pictures(&self) -> Result<Vec<Picture>> {
+        self.get_tag_multi(PICTURE_TAG)
+            .iter()
+            .map(|encoded| decode_picture_block(&STANDARD.decode(encoded)?))
+            .collect()
+    }
+
+    fn clear_pictures(&mut self) {
+        self.clear_tag(PICTURE_TAG);
+    }
+
+    fn to_text(&self) -> String {
+        self.comment_list
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, escape_text_value(value)))
+            .collect()
+    }
+
+    fn from_text(&mut self, s: &str) -> Result<()> {
+        let mut comment_list = Vec::new();
+        for line in s.lines() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts
+                .next()
+                .ok_or_else(|| Error::MalformedTextLine(line.to_string()))?;
+
+            if !validate_comment_field_name(key) {
+                return Err(Error::InvalidFieldName(key.to_string()));
+            }
+            comment_list.push((key.to_lowercase(), unescape_text_value(value)?));
+        }
+        self.comment_list = comment_list;
+        Ok(())
+    }
+}
 
+/// Write out a comment header for the given codec.
+pub fn make_comment_header(header: &CommentHeader, codec: Codec) -> Result<Vec<u8>> {
     // Vendor number of bytes as u32
     let vendor = header.vendor.as_bytes();
-    let vendor_len: u32 = vendor.len().try_into().unwrap();
-
-    // End byte
-    let end: u8 = 1;
+    let vendor_len: u32 = vendor.len().try_into().map_err(|_| Error::TooLarge)?;
 
     let mut new_packet: Vec<u8> = vec![];
 
-    // Write start
-    new_packet.extend(start.iter().cloned());
+    // Write the codec-specific comment signature, if any
+    match codec {
+        Codec::Vorbis => new_packet.extend(VORBIS_COMMENT_SIGNATURE.iter().cloned()),
+        Codec::Opus => new_packet.extend(OPUS_COMMENT_SIGNATURE.iter().cloned()),
+        Codec::Speex => {}
+    }
 
     // Write vendor
     new_packet.extend(vendor_len.to_le_bytes().iter().cloned());
     new_packet.extend(vendor.iter().cloned());
 
     // Write number of comments
-    let comment_nbr: u32 = header.comment_list.len().try_into().unwrap();
+    let comment_nbr: u32 = header
+        .comment_list
+        .len()
+        .try_into()
+        .map_err(|_| Error::TooLarge)?;
     new_packet.extend(comment_nbr.to_le_bytes().iter().cloned());
 
     let mut commentstrings: Vec<String> = vec![];
@@ -144,47 +586,191 @@ pub fn make_comment_header(header: &CommentHeader) -> Vec<u8> {
         let comment_len: u32 = commentstrings
             .last()
             .unwrap()
-            .as_bytes()
             .len()
             .try_into()
-            .unwrap();
+            .map_err(|_| Error::TooLarge)?;
         new_packet.extend(comment_len.to_le_bytes().iter().cloned());
         new_packet.extend(commentstrings.last().unwrap().as_bytes().iter().cloned());
     }
-    new_packet.push(end);
 
-    new_packet
+    // Only the Vorbis header packets are terminated with a framing bit.
+    if codec == Codec::Vorbis {
+        new_packet.push(1u8);
+    }
+
+    Ok(new_packet)
 }
 
-/// Read a comment header.
-pub fn read_comment_header<T: Read + Seek>(f_in: T) -> CommentHeader {
+/// Read a comment header, detecting the codec from the identification packet.
+pub fn read_comment_header<T: Read + Seek>(f_in: T) -> Result<CommentHeader> {
     let mut reader = PacketReader::new(f_in);
 
-    let packet: Packet = reader.read_packet_expected().unwrap();
-    let stream_serial = packet.stream_serial();
+    let ident_packet: Packet = reader.read_packet_expected()?;
+    let stream_serial = ident_packet.stream_serial();
+    let codec = detect_codec(&ident_packet.data).ok_or(Error::UnknownCodec)?;
 
-    let mut packet: Packet = reader.read_packet_expected().unwrap();
+    let mut packet: Packet = reader.read_packet_expected()?;
 
     while packet.stream_serial() != stream_serial {
-        packet = reader.read_packet_expected().unwrap();
+        packet = reader.read_packet_expected()?;
+    }
+
+    parse_comment_body(strip_comment_signature(codec, &packet.data)?)
+}
+
+/// The maximum number of lacing segments a single Ogg page can hold; with
+/// up to 255 bytes per segment, a page carries at most 65025 payload bytes.
+const MAX_PAGE_SEGMENTS: usize = 255;
+
+/// Split a packet of `len` bytes into the lacing values of an Ogg segment
+/// table: as many 255s as there are full 255-byte chunks, followed by the
+/// final (possibly zero-length) segment. A packet whose length is an exact
+/// multiple of 255 is therefore always terminated by an explicit zero.
+fn lace_lengths(len: usize) -> Vec<u8> {
+    let mut lengths = vec![255u8; len / 255];
+    lengths.push((len % 255) as u8);
+    lengths
+}
+
+/// Compute the Ogg page checksum: CRC-32 with the polynomial `0x04c11db7`,
+/// no reflection and no final XOR, over the page with its checksum field
+/// zeroed out.
+fn ogg_crc32(page: &[u8]) -> u32 {
+    let mut crc: u32 = 0;
+    for &byte in page {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Builds the Ogg pages of a single logical bitstream, splitting packets
+/// that don't fit in one page across as many continuation pages as needed
+/// and keeping page sequence numbers contiguous.
+struct OggMuxer {
+    sequence: u32,
+    began: bool,
+    segments: Vec<u8>,
+    payload: Vec<u8>,
+    pending_continued: bool,
+}
+
+impl OggMuxer {
+    fn new() -> OggMuxer {
+        OggMuxer {
+            sequence: 0,
+            began: false,
+            segments: Vec::new(),
+            payload: Vec::new(),
+            pending_continued: false,
+        }
+    }
+
+    /// Serialize the buffered segments/payload as one Ogg page.
+    fn flush_page<W: Write>(
+        &mut self,
+        out: &mut W,
+        stream_serial: u32,
+        granule_position: u64,
+        last_page: bool,
+    ) -> io::Result<()> {
+        if self.segments.is_empty() {
+            return Ok(());
+        }
+
+        let mut header_type = 0u8;
+        if self.pending_continued {
+            header_type |= 0x01;
+        }
+        if !self.began {
+            header_type |= 0x02;
+        }
+        if last_page {
+            header_type |= 0x04;
+        }
+
+        let mut page = Vec::with_capacity(27 + self.segments.len() + self.payload.len());
+        page.extend(b"OggS".iter().cloned());
+        page.push(0); // stream structure version
+        page.push(header_type);
+        page.extend(granule_position.to_le_bytes().iter().cloned());
+        page.extend(stream_serial.to_le_bytes().iter().cloned());
+        page.extend(self.sequence.to_le_bytes().iter().cloned());
+        page.extend([0u8; 4].iter().cloned()); // checksum, filled in below
+        page.push(self.segments.len() as u8);
+        page.extend(self.segments.iter().cloned());
+        page.extend(self.payload.iter().cloned());
+
+        let crc = ogg_crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        out.write_all(&page)?;
+
+        self.sequence += 1;
+        self.began = true;
+        self.pending_continued = false;
+        self.segments.clear();
+        self.payload.clear();
+        Ok(())
     }
 
-    lewton::header::read_header_comment(&packet.data).unwrap()
+    /// Write one packet, splitting it across as many pages as its lacing
+    /// table needs. `end_page` forces a page flush once the packet is fully
+    /// written (matching the source page boundary), and `last_page` marks
+    /// the final page of the logical bitstream.
+    fn write_packet<W: Write>(
+        &mut self,
+        out: &mut W,
+        stream_serial: u32,
+        data: &[u8],
+        granule_position: u64,
+        end_page: bool,
+        last_page: bool,
+    ) -> io::Result<()> {
+        let lengths = lace_lengths(data.len());
+        let last_index = lengths.len() - 1;
+        let mut offset = 0;
+        for (i, &seg_len) in lengths.iter().enumerate() {
+            self.segments.push(seg_len);
+            self.payload
+                .extend(data[offset..offset + seg_len as usize].iter().cloned());
+            offset += seg_len as usize;
+
+            if self.segments.len() == MAX_PAGE_SEGMENTS && i != last_index {
+                // The page is full but the packet isn't finished: flush what
+                // we have and carry the rest over as a continuation.
+                self.flush_page(out, stream_serial, granule_position, false)?;
+                self.pending_continued = true;
+            }
+        }
+
+        if end_page || self.segments.len() == MAX_PAGE_SEGMENTS {
+            self.flush_page(out, stream_serial, granule_position, end_page && last_page)?;
+        }
+        Ok(())
+    }
 }
 
 /// Replace the comment header of a file.
 pub fn replace_comment_header<T: Read + Seek>(
     f_in: T,
     new_header: CommentHeader,
-) -> Cursor<Vec<u8>> {
-    let new_comment_data = make_comment_header(&new_header);
-
+) -> Result<Cursor<Vec<u8>>> {
     let f_out_ram: Vec<u8> = vec![];
     let mut f_out = Cursor::new(f_out_ram);
 
     let mut reader = PacketReader::new(f_in);
-    let mut writer = PacketWriter::new(&mut f_out);
+    let mut muxers: HashMap<u32, OggMuxer> = HashMap::new();
 
+    // The identification packet's stream and codec, and whether we've
+    // already replaced that stream's comment packet.
+    let mut target: Option<(u32, Codec)> = None;
     let mut header_done = false;
     loop {
         let rp = reader.read_packet();
@@ -192,35 +778,33 @@ pub fn replace_comment_header<T: Read + Seek>(
             Ok(r) => {
                 match r {
                     Some(mut packet) => {
-                        let inf = if packet.last_in_stream() {
-                            PacketWriteEndInfo::EndStream
-                        } else if packet.last_in_page() {
-                            PacketWriteEndInfo::EndPage
-                        } else {
-                            PacketWriteEndInfo::NormalPacket
-                        };
-                        if !header_done {
-                            let comment_hdr = lewton::header::read_header_comment(&packet.data);
-                            match comment_hdr {
-                                Ok(_hdr) => {
-                                    // This is the packet to replace
-                                    packet.data = new_comment_data.clone();
-                                    header_done = true;
-                                }
-                                Err(_error) => {}
+                        if let Some((stream_serial, codec)) = target {
+                            if !header_done && packet.stream_serial() == stream_serial {
+                                // This is the packet to replace
+                                packet.data = make_comment_header(&new_header, codec)?;
+                                header_done = true;
                             }
+                        } else {
+                            let codec =
+                                detect_codec(&packet.data).ok_or(Error::UnknownCodec)?;
+                            target = Some((packet.stream_serial(), codec));
                         }
+
                         let lastpacket = packet.last_in_stream() && packet.last_in_page();
+                        let end_page = packet.last_in_page();
+                        let last_page = packet.last_in_stream();
                         let stream_serial = packet.stream_serial();
                         let absgp_page = packet.absgp_page();
-                        writer
-                            .write_packet(
-                                packet.data.into_boxed_slice(),
-                                stream_serial,
-                                inf,
-                                absgp_page,
-                            )
-                            .unwrap();
+
+                        let muxer = muxers.entry(stream_serial).or_insert_with(OggMuxer::new);
+                        muxer.write_packet(
+                            &mut f_out,
+                            stream_serial,
+                            &packet.data,
+                            absgp_page,
+                            end_page,
+                            last_page,
+                        )?;
                         if lastpacket {
                             break;
                         }
@@ -229,12 +813,342 @@ pub fn replace_comment_header<T: Read + Seek>(
                     None => break,
                 }
             }
-            Err(error) => {
-                println!("Error reading packet: {:?}", error);
-                break;
-            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+    f_out.seek(std::io::SeekFrom::Start(0))?;
+    Ok(f_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+    use std::io::Cursor;
+
+    fn sample_picture() -> Picture {
+        Picture {
+            picture_type: 3,
+            mime: "image/jpeg".to_string(),
+            description: "cover".to_string(),
+            width: 100,
+            height: 200,
+            depth: 24,
+            colors: 0,
+            data: vec![1, 2, 3, 4, 5],
+        }
+    }
+
+    #[test]
+    fn picture_block_round_trips() {
+        let picture = sample_picture();
+        let block = encode_picture_block(&picture);
+        assert_eq!(decode_picture_block(&block).unwrap(), picture);
+    }
+
+    #[test]
+    fn decode_picture_block_rejects_truncated_block() {
+        let block = encode_picture_block(&sample_picture());
+        let truncated = &block[..block.len() - 1];
+        assert!(decode_picture_block(truncated).is_err());
+    }
+
+    #[test]
+    fn decode_picture_block_rejects_oversized_declared_length() {
+        let mut block = Vec::new();
+        block.extend(0u32.to_be_bytes()); // picture_type
+        block.extend(0xFFFFFFu32.to_be_bytes()); // mime_len far beyond what follows
+        assert!(decode_picture_block(&block).is_err());
+    }
+
+    #[test]
+    fn add_get_clear_pictures_round_trips() {
+        let mut header = CommentHeader::new();
+        header.add_picture(3, "image/png", "front", 10, 20, 24, 0, &[9, 8, 7]);
+
+        let pictures = header.get_pictures().unwrap();
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].mime, "image/png");
+        assert_eq!(pictures[0].data, vec![9, 8, 7]);
+        assert!(!header.get_tag_names().contains(&PICTURE_TAG.to_string()));
+
+        header.clear_pictures();
+        assert!(header.get_pictures().unwrap().is_empty());
+    }
+
+    #[test]
+    fn detect_codec_from_ident_packets() {
+        assert_eq!(detect_codec(VORBIS_IDENT_SIGNATURE), Some(Codec::Vorbis));
+        assert_eq!(detect_codec(OPUS_IDENT_SIGNATURE), Some(Codec::Opus));
+        assert_eq!(detect_codec(SPEEX_IDENT_SIGNATURE), Some(Codec::Speex));
+        assert_eq!(detect_codec(b"garbage!"), None);
+    }
+
+    /// Build a minimal two-packet Ogg stream (identification + comment) and
+    /// return it as an in-memory byte vector.
+    fn build_ident_comment_stream(ident: &[u8], comment: &[u8]) -> Vec<u8> {
+        let mut writer = PacketWriter::new(Cursor::new(Vec::new()));
+        writer
+            .write_packet(ident.to_vec().into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0)
+            .unwrap();
+        writer
+            .write_packet(
+                comment.to_vec().into_boxed_slice(),
+                1,
+                PacketWriteEndInfo::EndStream,
+                0,
+            )
+            .unwrap();
+        writer.into_inner().into_inner()
+    }
+
+    #[test]
+    fn read_comment_header_parses_opus_stream() {
+        let comment = make_comment_header(
+            &CommentHeader {
+                vendor: "libopus".to_string(),
+                comment_list: vec![("TITLE".to_string(), "Track".to_string())],
+            },
+            Codec::Opus,
+        )
+        .unwrap();
+
+        let stream = build_ident_comment_stream(OPUS_IDENT_SIGNATURE, &comment);
+        let header = read_comment_header(Cursor::new(stream)).unwrap();
+        assert_eq!(header.vendor, "libopus");
+        assert_eq!(header.get_tag_single("title"), Some("Track"));
+    }
+
+    #[test]
+    fn read_comment_header_parses_speex_stream() {
+        let comment = make_comment_header(
+            &CommentHeader {
+                vendor: "speex-1.2".to_string(),
+                comment_list: vec![("ARTIST".to_string(), "Someone".to_string())],
+            },
+            Codec::Speex,
+        )
+        .unwrap();
+
+        let stream = build_ident_comment_stream(SPEEX_IDENT_SIGNATURE, &comment);
+        let header = read_comment_header(Cursor::new(stream)).unwrap();
+        assert_eq!(header.vendor, "speex-1.2");
+        assert_eq!(header.get_tag_single("artist"), Some("Someone"));
+    }
+
+    /// Walk the raw Ogg pages in `bytes`, returning each page's header-type
+    /// byte and page-sequence number, in stream order.
+    fn scan_pages(bytes: &[u8]) -> Vec<(u8, u32)> {
+        let mut pages = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            assert_eq!(&bytes[pos..pos + 4], b"OggS");
+            let header_type = bytes[pos + 5];
+            let seq = u32::from_le_bytes(bytes[pos + 18..pos + 22].try_into().unwrap());
+            let segment_count = bytes[pos + 26] as usize;
+            let segment_table = &bytes[pos + 27..pos + 27 + segment_count];
+            let payload_len: usize = segment_table.iter().map(|&b| b as usize).sum();
+            pages.push((header_type, seq));
+            pos += 27 + segment_count + payload_len;
+        }
+        pages
+    }
+
+    #[test]
+    fn replace_comment_header_splits_oversized_packet_across_pages() {
+        let serial = 42;
+        let mut writer = PacketWriter::new(Cursor::new(Vec::new()));
+
+        let mut ident_packet = VORBIS_IDENT_SIGNATURE.to_vec();
+        ident_packet.extend(vec![0u8; 20]);
+        writer
+            .write_packet(ident_packet.into_boxed_slice(), serial, PacketWriteEndInfo::EndPage, 0)
+            .unwrap();
+
+        let old_comment = make_comment_header(&CommentHeader::new(), Codec::Vorbis).unwrap();
+        writer
+            .write_packet(old_comment.into_boxed_slice(), serial, PacketWriteEndInfo::EndPage, 0)
+            .unwrap();
+
+        let audio_payload = vec![0xABu8; 100];
+        writer
+            .write_packet(
+                audio_payload.clone().into_boxed_slice(),
+                serial,
+                PacketWriteEndInfo::EndStream,
+                1000,
+            )
+            .unwrap();
+
+        let input = writer.into_inner().into_inner();
+
+        // A single tag value larger than one Ogg page (65025 bytes), forcing
+        // the rewritten comment packet to split across several pages.
+        let big_value = "x".repeat(70_000);
+        let new_header = CommentHeader {
+            vendor: "vorbis-test".to_string(),
+            comment_list: vec![("BIGTAG".to_string(), big_value.clone())],
+        };
+
+        let mut output = replace_comment_header(Cursor::new(input), new_header).unwrap();
+        let mut out_bytes = Vec::new();
+        output.read_to_end(&mut out_bytes).unwrap();
+
+        // The split comment packet reassembles correctly...
+        let reparsed = read_comment_header(Cursor::new(out_bytes.clone())).unwrap();
+        assert_eq!(reparsed.vendor, "vorbis-test");
+        assert_eq!(reparsed.get_tag_single("bigtag"), Some(big_value.as_str()));
+
+        // ...the audio packet that follows survived untouched...
+        let mut reader = PacketReader::new(Cursor::new(out_bytes.clone()));
+        let _ident = reader.read_packet_expected().unwrap();
+        let _comment = reader.read_packet_expected().unwrap();
+        let audio = reader.read_packet_expected().unwrap();
+        assert_eq!(&*audio.data, &audio_payload[..]);
+
+        // ...and the raw pages have contiguous sequence numbers, with the
+        // continued-packet flag (0x01) set on at least one page, the one
+        // that carries the continuation of the split comment packet.
+        let pages = scan_pages(&out_bytes);
+        assert!(
+            pages.len() > 3,
+            "the oversized comment packet should have split across more than one page"
+        );
+        for (i, (_, seq)) in pages.iter().enumerate() {
+            assert_eq!(*seq, i as u32);
+        }
+        let continuation_pages = pages.iter().filter(|(header_type, _)| header_type & 0x01 != 0).count();
+        assert!(continuation_pages >= 1);
+    }
+
+    #[test]
+    fn lace_lengths_under_one_segment() {
+        assert_eq!(lace_lengths(0), vec![0]);
+        assert_eq!(lace_lengths(42), vec![42]);
+        assert_eq!(lace_lengths(254), vec![254]);
+    }
+
+    #[test]
+    fn lace_lengths_exact_multiple_gets_terminal_zero() {
+        assert_eq!(lace_lengths(255), vec![255, 0]);
+        assert_eq!(lace_lengths(510), vec![255, 255, 0]);
+    }
+
+    #[test]
+    fn lace_lengths_spans_several_segments() {
+        assert_eq!(lace_lengths(300), vec![255, 45]);
+    }
+
+    #[test]
+    fn ogg_crc32_of_empty_page_is_zero() {
+        assert_eq!(ogg_crc32(&[]), 0);
+    }
+
+    #[test]
+    fn ogg_crc32_matches_known_vector() {
+        // The "OggS" magic alone, a fixed and easy-to-recompute-by-hand vector.
+        assert_eq!(ogg_crc32(b"OggS"), 0x5fb0a94f);
+    }
+
+    #[test]
+    fn read_length_reads_in_bounds_value() {
+        let body = [3u8, 0, 0, 0];
+        let mut pos = 0;
+        assert_eq!(read_length(&body, &mut pos).unwrap(), 3);
+        assert_eq!(pos, 4);
+    }
+
+    #[test]
+    fn read_length_rejects_truncated_prefix() {
+        let body = [0u8, 0];
+        let mut pos = 0;
+        assert!(matches!(
+            read_length(&body, &mut pos),
+            Err(Error::LengthOutOfBounds {
+                declared: 4,
+                remaining: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn read_string_rejects_length_exceeding_remaining_bytes() {
+        let body = [b'h', b'i'];
+        let mut pos = 0;
+        assert!(matches!(
+            read_string(&body, &mut pos, 10),
+            Err(Error::LengthOutOfBounds {
+                declared: 10,
+                remaining: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn read_string_reads_in_bounds_value() {
+        let body = *b"hello";
+        let mut pos = 0;
+        assert_eq!(read_string(&body, &mut pos, 5).unwrap(), "hello");
+        assert_eq!(pos, 5);
+    }
+
+    #[test]
+    fn validate_comment_field_name_accepts_spec_legal_names() {
+        assert!(validate_comment_field_name("TITLE"));
+        assert!(validate_comment_field_name("ARTIST"));
+        assert!(validate_comment_field_name("CUSTOM-FIELD_1!"));
+    }
+
+    #[test]
+    fn validate_comment_field_name_rejects_equals_sign() {
+        assert!(!validate_comment_field_name("TITLE=FOO"));
+    }
+
+    #[test]
+    fn validate_comment_field_name_rejects_control_characters() {
+        assert!(!validate_comment_field_name("TITLE\n"));
+        assert!(!validate_comment_field_name("TITLE\t"));
+    }
+
+    #[test]
+    fn validate_comment_field_name_rejects_non_ascii() {
+        assert!(!validate_comment_field_name("TITLÉ"));
+    }
+
+    #[test]
+    fn escape_text_value_escapes_backslash_and_newlines() {
+        assert_eq!(escape_text_value("a\\b\nc\rd"), "a\\\\b\\nc\\rd");
+    }
+
+    #[test]
+    fn escape_text_value_leaves_equals_unescaped() {
+        assert_eq!(escape_text_value("a=b"), "a=b");
+    }
+
+    #[test]
+    fn unescape_text_value_reverses_escape_text_value() {
+        assert_eq!(unescape_text_value("a\\\\b\\nc\\rd").unwrap(), "a\\b\nc\rd");
+    }
+
+    #[test]
+    fn unescape_text_value_rejects_unknown_escape() {
+        assert!(matches!(unescape_text_value("a\\qb"), Err(Error::InvalidEscape)));
+    }
+
+    #[test]
+    fn escape_unescape_round_trips_arbitrary_values() {
+        let values = [
+            "",
+            "plain",
+            "with\\backslash",
+            "with\nnewline",
+            "with\r\ncrlf",
+            "with=equals",
+            "mixed\\=\r\n\\stuff",
+        ];
+        for value in values {
+            let escaped = escape_text_value(value);
+            assert_eq!(unescape_text_value(&escaped).unwrap(), value);
         }
     }
-    f_out.seek(std::io::SeekFrom::Start(0)).unwrap();
-    f_out
 }